@@ -6,40 +6,102 @@
 //! Some codes are borrowed from <https://github.com/tikv/protobuf-build/blob/4e57d66934a5f45774ad41bbc8650028c430ad66/src/lib.rs>
 
 use std::{
+    collections::{BTreeMap, HashSet},
     env,
+    ffi::{OsStr, OsString},
     fs::{self, File},
-    io::Write,
+    io::{self, Write},
     path::{Path, PathBuf},
 };
 
+use protobuf::descriptor::FileDescriptorSet;
 use protoc::Protoc;
+use regex::Regex;
+
+bitflags::bitflags! {
+    /// Extra derives to blanket-add to every generated `struct`/`enum`,
+    /// modeled on the tikv protobuf-build `GenOpt` flags.
+    #[derive(Default)]
+    pub struct GenOpt: u32 {
+        /// Add `#[derive(Eq)]`.
+        const EQ = 0b0000_0001;
+        /// Add `#[derive(Hash)]`.
+        const HASH = 0b0000_0010;
+        /// Add `#[derive(serde::Serialize, serde::Deserialize)]`.
+        const SERDE = 0b0000_0100;
+    }
+}
+
+// Resolve the path to the protoc binary to use, preferring a user-provided
+// one: the `PROTOC` environment variable, then `protoc` on `PATH`, and only
+// falling back to the vendored binary if neither is usable.
+fn find_protoc_path() -> PathBuf {
+    if let Ok(path) = env::var("PROTOC") {
+        let path = PathBuf::from(path);
+        assert!(
+            Protoc::from_path(&path).version().unwrap().is_3(),
+            "`{}` (from the PROTOC environment variable) is not protobuf 3.x",
+            path.display()
+        );
+        return path;
+    }
+
+    let path = PathBuf::from("protoc");
+    if matches!(Protoc::from_path(&path).version(), Ok(version) if version.is_3()) {
+        return path;
+    }
 
-fn check_and_get_protoc_bin_path() -> PathBuf {
     let path = protoc_bin_vendored::protoc_bin_path().unwrap();
     assert!(Protoc::from_path(&path).version().unwrap().is_3());
     path
 }
 
+/// Code generation backend used by [`Builder::generate`].
+pub enum Codec {
+    /// Generate rust-protobuf + gRPC code via `protoc_grpcio`. This is the
+    /// default.
+    ProtobufGrpc,
+    /// Generate Prost/Tonic-style code from a protoc descriptor set.
+    #[cfg(feature = "prost")]
+    Prost,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::ProtobufGrpc
+    }
+}
+
 /// Rust code builder for protos
 pub struct Builder {
     /// Cargo output directory for protos
-    out_dir: String,
+    out_dir: PathBuf,
     /// Protobuf files to generate
-    files: Vec<String>,
-    /// Protobuf include directory
-    include_dir: String,
+    files: Vec<PathBuf>,
+    /// Protobuf include directories
+    include_dirs: Vec<PathBuf>,
+    /// Directory passed to `search_dir_for_protos`, if any
+    proto_dir: Option<PathBuf>,
+    /// Code generation backend
+    codec: Codec,
+    /// Derives to blanket-add to every generated message/enum
+    gen_opt: GenOpt,
+    /// `(pattern, attr)` pairs: `attr` is inserted above every generated
+    /// `struct`/`enum` whose name matches the regex `pattern`
+    message_attrs: Vec<(String, String)>,
 }
 
 impl Builder {
     /// Create a new Builder
     pub fn new() -> Self {
         Self {
-            out_dir: format!(
-                "{}/protos",
-                env::var("OUT_DIR").expect("No OUT_DIR defined")
-            ),
+            out_dir: PathBuf::from(env::var("OUT_DIR").expect("No OUT_DIR defined")).join("protos"),
             files: Vec::new(),
-            include_dir: "protos".to_string(),
+            include_dirs: vec![PathBuf::from("protos")],
+            proto_dir: None,
+            codec: Codec::default(),
+            gen_opt: GenOpt::empty(),
+            message_attrs: Vec::new(),
         }
     }
 
@@ -47,92 +109,516 @@ impl Builder {
     pub fn generate(&self) {
         assert!(!self.files.is_empty(), "No files specified for generation");
 
+        self.emit_rerun_if_changed();
         self.prepare_out_dir();
         self.generate_files();
-        self.generate_mod_file();
+
+        let packages = self.file_packages();
+        self.postprocess_generated_files(&packages);
+        self.generate_mod_file(&packages);
+    }
+
+    // Maps each proto input file's stem to its declared `package` (see
+    // `read_file_packages`), computed once per `generate()` call and shared
+    // by `postprocess_generated_files` and `generate_mod_file`. Skipped for
+    // `Codec::Prost`, whose `generate_prost_files` already decoded an
+    // equivalent descriptor set moments earlier and whose output filenames
+    // are keyed by package rather than proto stem, so the lookup would
+    // never hit anyway — running it regardless would just shell out to
+    // protoc a second time for nothing.
+    fn file_packages(&self) -> BTreeMap<String, String> {
+        match self.codec {
+            Codec::ProtobufGrpc => self.read_file_packages(),
+            #[cfg(feature = "prost")]
+            Codec::Prost => BTreeMap::new(),
+        }
+    }
+
+    // Tell Cargo which files generation depends on, so edits to them
+    // reliably retrigger `generate()` and unrelated changes don't.
+    fn emit_rerun_if_changed(&self) {
+        for file in &self.files {
+            println!("cargo:rerun-if-changed={}", file.display());
+        }
+
+        for include_dir in &self.include_dirs {
+            println!("cargo:rerun-if-changed={}", include_dir.display());
+        }
+
+        if let Some(proto_dir) = &self.proto_dir {
+            println!("cargo:rerun-if-changed={}", proto_dir.display());
+        }
     }
 
     /// Set `out_dir`, default is `$OUT_DIR/protos`
-    pub fn out_dir(&mut self, out_dir: impl Into<String>) -> &mut Self {
-        self.out_dir = out_dir.into();
+    pub fn out_dir(&mut self, out_dir: impl AsRef<Path>) -> &mut Self {
+        self.out_dir = out_dir.as_ref().to_path_buf();
+        self
+    }
+
+    /// Add a single protobuf file to generate. Composes with
+    /// [`Builder::search_dir_for_protos`] in either call order: both add to
+    /// the file list rather than replacing it.
+    pub fn file(&mut self, file: impl AsRef<Path>) -> &mut Self {
+        self.files.push(file.as_ref().to_path_buf());
+        self
+    }
+
+    /// Set a single protobuf include directory, default is `protos`. A
+    /// convenience wrapper around [`Builder::include_dirs`] for the common
+    /// case of a single include root.
+    pub fn include_dir(&mut self, include_dir: impl AsRef<Path>) -> &mut Self {
+        self.include_dirs(vec![include_dir])
+    }
+
+    /// Set the protobuf include directories, for proto sets whose files
+    /// import across several roots.
+    pub fn include_dirs(
+        &mut self,
+        include_dirs: impl IntoIterator<Item = impl AsRef<Path>>,
+    ) -> &mut Self {
+        self.include_dirs = include_dirs
+            .into_iter()
+            .map(|dir| dir.as_ref().to_path_buf())
+            .collect();
+        self
+    }
+
+    /// Select the code generation backend, default is
+    /// [`Codec::ProtobufGrpc`].
+    pub fn codec(&mut self, codec: Codec) -> &mut Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Blanket-add derives to every generated message/enum, see [`GenOpt`].
+    pub fn gen_opt(&mut self, gen_opt: GenOpt) -> &mut Self {
+        self.gen_opt = gen_opt;
+        self
+    }
+
+    /// Insert `attr` (e.g. `"#[derive(serde::Serialize)]"`) directly above
+    /// every generated `pub struct`/`pub enum` whose `package.MessageName`
+    /// matches the regex `pattern`, so same-named messages in different
+    /// packages can be targeted independently. Under [`Codec::Prost`], whose
+    /// generated files are not mapped back to a proto package,
+    /// `pattern` matches against the bare `MessageName` instead. An escape
+    /// hatch for attributes [`Builder::gen_opt`] doesn't cover.
+    pub fn message_attr(
+        &mut self,
+        pattern: impl Into<String>,
+        attr: impl Into<String>,
+    ) -> &mut Self {
+        self.message_attrs.push((pattern.into(), attr.into()));
         self
     }
 
     fn prepare_out_dir(&self) {
-        if Path::new(&self.out_dir).exists() {
+        if self.out_dir.exists() {
             fs::remove_dir_all(&self.out_dir).unwrap();
         }
         fs::create_dir_all(&self.out_dir).unwrap();
     }
 
     fn generate_files(&self) {
+        match self.codec {
+            Codec::ProtobufGrpc => self.generate_protobuf_grpc_files(),
+            #[cfg(feature = "prost")]
+            Codec::Prost => self.generate_prost_files(),
+        }
+    }
+
+    fn generate_protobuf_grpc_files(&self) {
+        self.assert_unique_file_stems();
+
         protoc_grpcio::compile_grpc_protos(
             // inputs
             &self.files,
             // includes
-            &[&self.include_dir],
+            &self.include_dirs,
             // output
             &self.out_dir,
             // customizations
             None,
             // protoc path
-            Some(Protoc::from_path(&check_and_get_protoc_bin_path())),
+            Some(Protoc::from_path(&find_protoc_path())),
         )
         .expect("Failed to compile protobuf and grpc files");
     }
 
-    fn generate_mod_file(&self) {
-        let mut f = File::create(format!("{}/mod.rs", self.out_dir)).unwrap();
+    // protoc_grpcio (like the rust-protobuf codegen it wraps) names each
+    // generated file after its input's basename, ignoring directory — so
+    // recursively discovered protos that share a basename (e.g.
+    // `v1/service.proto` and `v2/service.proto`, found via
+    // `search_dir_for_protos`) would otherwise silently overwrite each
+    // other's generated code in `out_dir`.
+    fn assert_unique_file_stems(&self) {
+        let mut seen = HashSet::new();
+        for file in &self.files {
+            let stem = file.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+            assert!(
+                seen.insert(stem.to_owned()),
+                "multiple proto files named `{stem}.proto` were found; protoc_grpcio \
+                 names generated files after the proto basename only, so generating \
+                 both would silently overwrite one with the other (rename one of \
+                 them, or use Codec::Prost, which names files by package instead)"
+            );
+        }
+    }
+
+    // Generate Prost/Tonic-style code via a protoc descriptor set: ask
+    // protoc to dump a `FileDescriptorSet`, decode it, then hand the
+    // files-to-generate list to prost codegen.
+    #[cfg(feature = "prost")]
+    fn generate_prost_files(&self) {
+        let descriptor_path = self.out_dir.join("mod.desc");
 
-        let mut modules: Vec<_> = self
-            .list_rs_files()
-            .filter_map(|path| {
-                let name = path.file_stem().unwrap().to_str().unwrap();
-                if name == "mod" {
-                    return None;
-                }
+        let mut out_arg = OsString::from("-o");
+        out_arg.push(&descriptor_path);
 
-                Some(name.to_owned())
+        let mut command = std::process::Command::new(find_protoc_path());
+        command
+            .arg("--include_imports")
+            .arg("--include_source_info")
+            .arg(out_arg);
+        for include_dir in &self.include_dirs {
+            let mut include_arg = OsString::from("-I");
+            include_arg.push(include_dir);
+            command.arg(include_arg);
+        }
+        command.args(&self.files);
+
+        let status = command.status().expect("Failed to run protoc");
+        assert!(status.success(), "protoc exited with {status}");
+
+        let bytes = fs::read(&descriptor_path).expect("Couldn't read descriptor set");
+        let descriptor_set: prost_types::FileDescriptorSet =
+            prost::Message::decode(bytes.as_slice()).expect("Couldn't decode descriptor set");
+
+        let files_to_generate = self
+            .files
+            .iter()
+            .map(|file| file.to_string_lossy().into_owned())
+            .collect();
+
+        prost_build::Config::new()
+            .out_dir(&self.out_dir)
+            .generate(descriptor_set, files_to_generate)
+            .expect("Failed to generate prost code");
+    }
+
+    // Rewrite generated `.rs` files in place, inserting the attributes
+    // configured via `gen_opt`/`message_attr` above every matching
+    // `pub struct`/`pub enum` declaration.
+    fn postprocess_generated_files(&self, packages: &BTreeMap<String, String>) {
+        if self.gen_opt.is_empty() && self.message_attrs.is_empty() {
+            return;
+        }
+
+        let header_re = Regex::new(r"^\s*pub (?:struct|enum) (\w+)").unwrap();
+        let message_attrs: Vec<(Regex, &str)> = self
+            .message_attrs
+            .iter()
+            .map(|(pattern, attr)| {
+                (
+                    Regex::new(pattern).expect("Invalid message_attr pattern"),
+                    attr.as_str(),
+                )
             })
             .collect();
+        let gen_opt_attrs = self.gen_opt_attrs();
+
+        for path in self.list_rs_files() {
+            let stem = path.file_stem().and_then(OsStr::to_str).unwrap_or_default();
+            if stem.ends_with("_grpc") {
+                // protoc_grpcio emits gRPC service/client/server plumbing here
+                // (e.g. `FooClient`, `FooServer`); `gen_opt`/`message_attr`
+                // only target protobuf message/enum types.
+                continue;
+            }
+
+            // Only known for the default `ProtobufGrpc` backend (see
+            // `file_packages`); `message_attr` patterns match against the
+            // bare message name when it's unavailable.
+            let package = packages.get(stem).map(String::as_str).unwrap_or("");
+
+            let contents = fs::read_to_string(&path).unwrap();
+            let mut rewritten = String::with_capacity(contents.len());
+
+            for line in contents.lines() {
+                if let Some(caps) = header_re.captures(line) {
+                    let name = &caps[1];
+                    let qualified_name = if package.is_empty() {
+                        name.to_string()
+                    } else {
+                        format!("{package}.{name}")
+                    };
+
+                    for attr in &gen_opt_attrs {
+                        rewritten.push_str(attr);
+                        rewritten.push('\n');
+                    }
+
+                    for (pattern, attr) in &message_attrs {
+                        if pattern.is_match(&qualified_name) {
+                            rewritten.push_str(attr);
+                            rewritten.push('\n');
+                        }
+                    }
+                }
 
-        modules.sort();
+                rewritten.push_str(line);
+                rewritten.push('\n');
+            }
 
-        for module in modules {
-            writeln!(f, "pub mod {};", module).unwrap();
+            fs::write(&path, rewritten).unwrap();
         }
     }
 
-    // List all `.rs` files in `out_dir`
-    fn list_rs_files(&self) -> impl Iterator<Item = PathBuf> {
-        fs::read_dir(&self.out_dir)
-            .expect("Couldn't read directory")
-            .filter_map(|e| {
-                let path = e.expect("Couldn't list file").path();
-                if path.extension() == Some(std::ffi::OsStr::new("rs")) {
-                    Some(path)
-                } else {
-                    None
-                }
-            })
+    fn gen_opt_attrs(&self) -> Vec<&'static str> {
+        let mut attrs = Vec::new();
+
+        if self.gen_opt.contains(GenOpt::EQ) {
+            attrs.push("#[derive(Eq)]");
+        }
+        if self.gen_opt.contains(GenOpt::HASH) {
+            attrs.push("#[derive(Hash)]");
+        }
+        if self.gen_opt.contains(GenOpt::SERDE) {
+            attrs.push("#[derive(serde::Serialize, serde::Deserialize)]");
+        }
+
+        attrs
     }
 
-    /// Finds proto files to operate on in the `proto_dir` directory.
-    pub fn search_dir_for_protos(&mut self, proto_dir: &str) -> &mut Self {
-        self.files = fs::read_dir(proto_dir)
-            .expect("Couldn't read proto directory")
-            .filter_map(|e| {
-                let e = e.expect("Couldn't list file");
-                let path = e.path();
-                if e.file_type().expect("File broken").is_dir()
-                    || path.extension() != Some(std::ffi::OsStr::new("proto"))
-                {
-                    None
-                } else {
-                    Some(format!("{}/{}", proto_dir, e.file_name().to_string_lossy()))
+    fn generate_mod_file(&self, packages: &BTreeMap<String, String>) {
+        let mut f = File::create(self.out_dir.join("mod.rs")).unwrap();
+
+        let mut tree = ModuleNode::default();
+        for path in self.list_rs_files() {
+            let stem = path
+                .file_stem()
+                .map(OsStr::to_string_lossy)
+                .unwrap_or_default();
+            if stem == "mod" {
+                continue;
+            }
+
+            let file_name = path.file_name().unwrap().to_string_lossy().into_owned();
+            if stem == "_" {
+                // protoc writes generated code for the empty package to `_.rs`;
+                // include it at the crate root instead of nesting it under a
+                // module named `_`.
+                tree.insert(&[], file_name);
+                continue;
+            }
+
+            // protoc_grpcio names the gRPC service stub file
+            // `<basename>_grpc.rs`; it shares its package with the sibling
+            // message file.
+            let message_stem = stem.strip_suffix("_grpc").unwrap_or(&stem);
+
+            let segments: Vec<String> = match packages.get(message_stem) {
+                // The default `ProtobufGrpc` backend names output files
+                // after the input proto's basename, so the real package has
+                // to be looked up from the descriptor set.
+                Some(package) if !package.is_empty() => {
+                    package.split('.').map(to_snake_case).collect()
                 }
+                Some(_) => Vec::new(),
+                // Backends like `Prost` name output files after the dotted
+                // package path directly; fall back to splitting the stem.
+                None => stem.split('.').map(to_snake_case).collect(),
+            };
+            tree.insert(&segments, file_name);
+        }
+
+        tree.write(&mut f, 0).unwrap();
+    }
+
+    // Maps each proto input file's stem (e.g. `foo` for `a/foo.proto`) to
+    // its declared `package`, by asking protoc for a descriptor set. Called
+    // via `file_packages` (once per `generate()`), this is what lets
+    // `generate_mod_file` nest generated code by package for backends (like
+    // the default `ProtobufGrpc` one) whose output filenames don't already
+    // encode it.
+    fn read_file_packages(&self) -> BTreeMap<String, String> {
+        let descriptor_path = self.out_dir.join("mod.desc");
+
+        let mut out_arg = OsString::from("-o");
+        out_arg.push(&descriptor_path);
+
+        let mut command = std::process::Command::new(find_protoc_path());
+        command.arg("--include_imports").arg(out_arg);
+        for include_dir in &self.include_dirs {
+            let mut include_arg = OsString::from("-I");
+            include_arg.push(include_dir);
+            command.arg(include_arg);
+        }
+        command.args(&self.files);
+
+        let status = command.status().expect("Failed to run protoc");
+        assert!(status.success(), "protoc exited with {status}");
+
+        let bytes = fs::read(&descriptor_path).expect("Couldn't read descriptor set");
+        let descriptor_set =
+            FileDescriptorSet::parse_from_bytes(&bytes).expect("Couldn't decode descriptor set");
+
+        descriptor_set
+            .file
+            .into_iter()
+            .filter_map(|file| {
+                let stem = Path::new(file.get_name()).file_stem()?.to_str()?.to_owned();
+                Some((stem, file.get_package().to_owned()))
             })
-            .collect();
+            .collect()
+    }
+
+    // Recursively list all `.rs` files under `out_dir`.
+    fn list_rs_files(&self) -> impl Iterator<Item = PathBuf> {
+        find_files_with_extension(&self.out_dir, "rs").into_iter()
+    }
+
+    /// Finds proto files to operate on, recursing into subdirectories of
+    /// `proto_dir` so multi-package proto trees are fully discovered. Adds
+    /// to (rather than replaces) the file list, so it composes with
+    /// [`Builder::file`] and with prior calls to this method; paths already
+    /// present are not added again.
+    pub fn search_dir_for_protos(&mut self, proto_dir: impl AsRef<Path>) -> &mut Self {
+        let proto_dir = proto_dir.as_ref();
+        for file in find_files_with_extension(proto_dir, "proto") {
+            if !self.files.contains(&file) {
+                self.files.push(file);
+            }
+        }
+        self.proto_dir = Some(proto_dir.to_path_buf());
         self
     }
 }
+
+// Recursively collects all files under `dir` with the given extension, in
+// the style of the Bazel prost wrapper's `find_generated_rust_files`.
+fn find_files_with_extension(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    collect_files_with_extension(dir, extension, &mut files);
+    files
+}
+
+fn collect_files_with_extension(dir: &Path, extension: &str, files: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(dir).expect("Couldn't read directory") {
+        let entry = entry.expect("Couldn't list file");
+        let path = entry.path();
+        if entry.file_type().expect("File broken").is_dir() {
+            collect_files_with_extension(&path, extension, files);
+        } else if path.extension() == Some(OsStr::new(extension)) {
+            files.push(path);
+        }
+    }
+}
+
+// snake_case a single proto package segment, e.g. `fooBar` -> `foo_bar`.
+fn to_snake_case(segment: &str) -> String {
+    let mut out = String::with_capacity(segment.len());
+    for (i, c) in segment.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            out.push('_');
+        }
+        out.extend(c.to_lowercase());
+    }
+    out
+}
+
+/// A node in the nested module tree built from generated files' proto
+/// packages, e.g. package `a.b` produces `pub mod a { pub mod b { .. } }`.
+#[derive(Default)]
+struct ModuleNode {
+    // The generated files to `include!` at this node, if any.
+    include_files: Vec<String>,
+    children: BTreeMap<String, ModuleNode>,
+}
+
+impl ModuleNode {
+    fn insert(&mut self, segments: &[String], file_name: String) {
+        match segments.split_first() {
+            None => self.include_files.push(file_name),
+            Some((head, rest)) => self
+                .children
+                .entry(head.clone())
+                .or_default()
+                .insert(rest, file_name),
+        }
+    }
+
+    fn write(&self, f: &mut impl Write, depth: usize) -> io::Result<()> {
+        let indent = "    ".repeat(depth);
+
+        for file_name in &self.include_files {
+            writeln!(f, "{indent}include!(\"{file_name}\");")?;
+        }
+
+        for (name, child) in &self.children {
+            writeln!(f, "{indent}pub mod {name} {{")?;
+            child.write(f, depth + 1)?;
+            writeln!(f, "{indent}}}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_snake_case_converts_camel_case() {
+        assert_eq!(to_snake_case("foo"), "foo");
+        assert_eq!(to_snake_case("fooBar"), "foo_bar");
+        assert_eq!(to_snake_case("FooBarBaz"), "foo_bar_baz");
+    }
+
+    #[test]
+    fn module_node_nests_by_inserted_segments() {
+        let mut tree = ModuleNode::default();
+        tree.insert(&[], "_.rs".to_string());
+        tree.insert(&["a".to_string()], "a.rs".to_string());
+        tree.insert(&["a".to_string(), "b".to_string()], "a.b.rs".to_string());
+
+        let mut out = Vec::new();
+        tree.write(&mut out, 0).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "include!(\"_.rs\");\n\
+             pub mod a {\n    \
+                 include!(\"a.rs\");\n    \
+                 pub mod b {\n        \
+                     include!(\"a.b.rs\");\n    \
+                 }\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn find_files_with_extension_recurses_into_subdirectories() {
+        let dir = env::temp_dir().join(format!(
+            "protobuf-builder-test-{}-{}",
+            std::process::id(),
+            "find-files-with-extension"
+        ));
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        fs::write(dir.join("top.proto"), "").unwrap();
+        fs::write(dir.join("nested/inner.proto"), "").unwrap();
+        fs::write(dir.join("nested/ignored.txt"), "").unwrap();
+
+        let mut found: Vec<String> = find_files_with_extension(&dir, "proto")
+            .into_iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect();
+        found.sort();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(found, vec!["inner.proto", "top.proto"]);
+    }
+}